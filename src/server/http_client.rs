@@ -0,0 +1,1081 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::io::ErrorKind::WouldBlock;
+use std::io::Read;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+use mio::tcp::TcpStream;
+use mio::{EventLoop, EventSet, Handler, PollOpt, Sender, Token};
+
+pub use url::Url;
+
+use kvproto::msgpb::Message;
+use protobuf::Message as PbMessage;
+
+use super::{Error, Result};
+use super::http::{Body, ChunkedBody, Compression, OnMessage, OnResponse, OnResponseResult};
+use super::http_server::V1_MSG_PATH;
+use super::http_ws::{self, FrameDecoder};
+use super::tls::{TlsConfig, TlsSession};
+
+enum ClientMsg {
+    Post(Url, Message, OnResponse),
+    PostChunked(Url, Message, OnResponse),
+    OpenChannel(Url, OnMessage, mpsc::Sender<Result<Token>>),
+    ChannelSend(Token, Message),
+    Shutdown,
+}
+
+enum ConnState {
+    Connecting,
+    WritingRequest,
+    ReadingHeader,
+    ReadingBody(usize),
+    ReadingChunked,
+    /// Parked in the idle pool after a keep-alive response: registered for
+    /// readable only, so a hangup or stray byte from the peer is treated as
+    /// the connection having gone bad and evicts it.
+    Idle,
+}
+
+struct Conn {
+    sock: TcpStream,
+    tls: Option<TlsSession>,
+    state: ConnState,
+    req: Body,
+    resp_header: Vec<u8>,
+    resp_body: Body,
+    resp_chunked: ChunkedBody,
+    resp_compression: Compression,
+    cb: Option<OnResponse>,
+    /// `host:port` of the peer, used to key the idle pool.
+    key: String,
+    idle_since: Option<Instant>,
+}
+
+impl Conn {
+    fn finish(&mut self, result: OnResponseResult) {
+        if let Some(cb) = self.cb.take() {
+            cb.call_box((result,))
+        }
+    }
+}
+
+/// Tuning knobs for `Client`'s idle-connection pool: at most
+/// `max_idle_per_host` connections are kept alive per destination
+/// `host:port`, and an idle connection older than `idle_timeout` is closed
+/// rather than reused.
+#[derive(Clone, Copy)]
+pub struct PoolConfig {
+    pub max_idle_per_host: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            max_idle_per_host: 2,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How often the idle pool is swept for connections past `idle_timeout`.
+const POOL_SWEEP_INTERVAL_MS: u64 = 5_000;
+
+enum ChannelState {
+    Connecting,
+    Handshaking,
+    Open,
+}
+
+/// A `Client` connection that stays open after its HTTP `Upgrade`
+/// handshake completes, relaying `msgpb::Message`s as binary frames in
+/// both directions instead of one request/response per message.
+struct ChannelConn {
+    sock: TcpStream,
+    tls: Option<TlsSession>,
+    state: ChannelState,
+    handshake_req: Body,
+    handshake_resp: Vec<u8>,
+    out: Body,
+    ws: FrameDecoder,
+    on_message: OnMessage,
+}
+
+struct ClientLoop {
+    tls_config: Option<Arc<::rustls::ClientConfig>>,
+    compression: Compression,
+    pool_config: PoolConfig,
+    conns: HashMap<Token, Conn>,
+    /// Idle, keep-alive connections available for reuse, keyed by
+    /// destination `host:port`. A `Token` only ever appears in one bucket
+    /// at a time; its `Conn` stays in `conns` the whole time it's pooled.
+    idle: HashMap<String, Vec<Token>>,
+    channels: HashMap<Token, ChannelConn>,
+    next_token: usize,
+}
+
+impl Handler for ClientLoop {
+    type Timeout = ();
+    type Message = ClientMsg;
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: ClientMsg) {
+        match msg {
+            ClientMsg::Post(url, msg, cb) => self.post(event_loop, url, msg, cb, false),
+            ClientMsg::PostChunked(url, msg, cb) => self.post(event_loop, url, msg, cb, true),
+            ClientMsg::OpenChannel(url, on_message, result_tx) => {
+                self.open_channel(event_loop, url, on_message, result_tx)
+            }
+            ClientMsg::ChannelSend(token, msg) => self.channel_send(event_loop, token, msg),
+            ClientMsg::Shutdown => event_loop.shutdown(),
+        }
+    }
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
+        if self.channels.contains_key(&token) {
+            let failed = {
+                let chan = self.channels.get_mut(&token).unwrap();
+                channel_pump(chan, events).is_err()
+            };
+            if failed {
+                if let Some(chan) = self.channels.remove(&token) {
+                    event_loop.deregister(&chan.sock).ok();
+                }
+            }
+            return;
+        }
+
+        if self.is_idle(token) {
+            // Anything happening on a parked connection - the peer
+            // writing, half-closing, or erroring out - means it's no
+            // longer safe to hand back out of the pool.
+            self.evict_idle(event_loop, token);
+            return;
+        }
+
+        let done = {
+            let conn = match self.conns.get_mut(&token) {
+                Some(c) => c,
+                None => return,
+            };
+            pump(conn, events)
+        };
+        if let Some(result) = done {
+            let keep_alive = result.is_ok() &&
+                              self.conns
+                                  .get(&token)
+                                  .map_or(false, |conn| is_keep_alive(&conn.resp_header));
+            if let Some(conn) = self.conns.get_mut(&token) {
+                conn.finish(result);
+            }
+            if keep_alive {
+                self.park(event_loop, token);
+            } else if let Some(conn) = self.conns.remove(&token) {
+                event_loop.deregister(&conn.sock).ok();
+            }
+        }
+    }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, _timeout: ()) {
+        self.sweep_idle(event_loop);
+        let _ = event_loop.timeout_ms((), POOL_SWEEP_INTERVAL_MS);
+    }
+}
+
+/// Drives one connection's handshake/request/response state machine one
+/// step, the same `WouldBlock`-tolerant way `Body::read_from`/`write_to`
+/// already pump plaintext sockets. Returns `Some(result)` once the
+/// response has fully arrived (or the connection has failed).
+fn pump(conn: &mut Conn, events: EventSet) -> Option<OnResponseResult> {
+    if let ConnState::Connecting = conn.state {
+        if events.is_writable() || events.is_error() || events.is_hup() {
+            conn.state = ConnState::WritingRequest;
+        } else {
+            return None;
+        }
+    }
+
+    if events.is_writable() {
+        if let ConnState::WritingRequest = conn.state {
+            if let Err(e) = write_request(conn) {
+                return Some(Err(e));
+            }
+        }
+        if let Some(ref mut tls) = conn.tls {
+            if let Err(e) = tls.pump_writable(&mut conn.sock) {
+                return Some(Err(e));
+            }
+        }
+    }
+
+    if events.is_readable() {
+        if conn.tls.is_some() {
+            let mut plain = Vec::new();
+            let read = {
+                let tls = conn.tls.as_mut().unwrap();
+                tls.pump_readable(&mut conn.sock, &mut plain)
+            };
+            if let Err(e) = read {
+                return Some(Err(e));
+            }
+            if !plain.is_empty() {
+                feed_response(conn, &plain);
+            }
+        } else {
+            let mut buf = [0u8; 4096];
+            match conn.sock.read(&mut buf) {
+                Ok(0) => return Some(Err(box_err!("remote has closed the connection"))),
+                Ok(n) => feed_response(conn, &buf[..n]),
+                Err(ref e) if e.kind() == WouldBlock => {}
+                Err(e) => return Some(Err(Error::Io(e))),
+            }
+        }
+    }
+
+    response_if_ready(conn)
+}
+
+fn write_request(conn: &mut Conn) -> Result<()> {
+    match conn.tls {
+        Some(ref mut tls) => {
+            if conn.req.remaining() > 0 {
+                let data = conn.req.as_bytes().to_vec();
+                tls.write_plaintext(&data)?;
+                conn.req.pos = conn.req.len();
+            }
+        }
+        None => conn.req.write_to(&mut conn.sock)?,
+    }
+    if conn.req.remaining() == 0 {
+        conn.state = ConnState::ReadingHeader;
+    }
+    Ok(())
+}
+
+fn feed_response(conn: &mut Conn, plain: &[u8]) {
+    match conn.state {
+        ConnState::ReadingHeader => conn.resp_header.extend_from_slice(plain),
+        ConnState::ReadingBody(_) => conn.resp_body.as_mut().extend_from_slice(plain),
+        ConnState::ReadingChunked => {
+            let _ = conn.resp_chunked.feed(plain);
+        }
+        _ => {}
+    }
+}
+
+/// Once the response header (terminated by `\r\n\r\n`) has arrived, switch
+/// to reading the body either by its `Content-Length` or, if the server
+/// replied with `Transfer-Encoding: chunked`, by decoding it incrementally
+/// through `ChunkedBody`.
+fn response_if_ready(conn: &mut Conn) -> Option<OnResponseResult> {
+    if let ConnState::ReadingHeader = conn.state {
+        if let Some(pos) = find_header_end(&conn.resp_header) {
+            let body_start = conn.resp_header.split_off(pos);
+            conn.resp_compression = compression_header(&conn.resp_header);
+            if is_chunked(&conn.resp_header) {
+                let _ = conn.resp_chunked.feed(&body_start);
+                conn.state = ConnState::ReadingChunked;
+            } else {
+                let len = content_length(&conn.resp_header).unwrap_or(0);
+                conn.resp_body.reset(len);
+                conn.resp_body.as_mut().clear();
+                conn.resp_body.as_mut().extend_from_slice(&body_start);
+                conn.resp_body.pos = body_start.len().min(len);
+                conn.state = ConnState::ReadingBody(len);
+            }
+        }
+    }
+
+    if let ConnState::ReadingBody(len) = conn.state {
+        // Completion has to be driven off the accumulated length, not
+        // `pos`: `feed_response` only ever appends to `resp_body`, while
+        // `pos` is only ever set once, right after the header is parsed,
+        // so it never reflects a body that arrives split across reads.
+        if conn.resp_body.as_bytes().len() >= len {
+            return Some(decode(conn.resp_compression, conn.resp_body.as_bytes()));
+        }
+    }
+
+    if let ConnState::ReadingChunked = conn.state {
+        if conn.resp_chunked.is_done() {
+            return Some(decode(conn.resp_compression, &conn.resp_chunked.data));
+        }
+    }
+
+    None
+}
+
+fn decode(compression: Compression, body: &[u8]) -> OnResponseResult {
+    let body = compression.decompress(body)?;
+    let mut msg = Message::new();
+    match msg.merge_from_bytes(&body) {
+        Ok(()) => Ok(Some(msg)),
+        Err(e) => Err(box_err!("failed to decode response: {:?}", e)),
+    }
+}
+
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4)
+}
+
+fn content_length(header: &[u8]) -> Option<usize> {
+    let text = String::from_utf8_lossy(header);
+    text.lines()
+        .find(|l| l.to_lowercase().starts_with("content-length:"))
+        .and_then(|l| l.splitn(2, ':').nth(1))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn is_chunked(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header);
+    text.lines()
+        .any(|l| {
+            l.to_lowercase().starts_with("transfer-encoding:") && l.to_lowercase().contains("chunked")
+        })
+}
+
+fn compression_header(header: &[u8]) -> Compression {
+    let text = String::from_utf8_lossy(header);
+    text.lines()
+        .find(|l| l.to_lowercase().starts_with("x-compression:"))
+        .and_then(|l| l.splitn(2, ':').nth(1))
+        .map(Compression::parse)
+        .unwrap_or(Compression::None)
+}
+
+/// Whether the response invited the connection to be pooled and reused.
+fn is_keep_alive(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header);
+    text.lines()
+        .any(|l| l.to_lowercase().starts_with("connection:") && l.to_lowercase().contains("keep-alive"))
+}
+
+fn host_port_key(url: &Url) -> Option<String> {
+    url.host_str().map(|host| format!("{}:{}", host, url.port().unwrap_or(80)))
+}
+
+/// Pops an idle connection for `key` out of `idle`, if one is available,
+/// pruning the bucket if it's left empty.
+fn idle_checkout(idle: &mut HashMap<String, Vec<Token>>, key: &str) -> Option<Token> {
+    let token = match idle.get_mut(key) {
+        Some(tokens) => tokens.pop(),
+        None => None,
+    };
+    if let Some(tokens) = idle.get(key) {
+        if tokens.is_empty() {
+            idle.remove(key);
+        }
+    }
+    token
+}
+
+/// Parks `token` in `idle`'s bucket for `key`, evicting and returning the
+/// oldest entry if that pushes the bucket past `max_idle_per_host`.
+fn idle_park(idle: &mut HashMap<String, Vec<Token>>,
+              key: String,
+              token: Token,
+              max_idle_per_host: usize)
+              -> Option<Token> {
+    let bucket = idle.entry(key).or_insert_with(Vec::new);
+    bucket.push(token);
+    if bucket.len() > max_idle_per_host {
+        Some(bucket.remove(0))
+    } else {
+        None
+    }
+}
+
+/// Removes `token` from whichever bucket of `idle` it's in, pruning any
+/// bucket that's left empty.
+fn idle_evict(idle: &mut HashMap<String, Vec<Token>>, token: Token) {
+    for tokens in idle.values_mut() {
+        tokens.retain(|&t| t != token);
+    }
+    idle.retain(|_, tokens| !tokens.is_empty());
+}
+
+/// Drives one `Channel` connection's handshake / frame relay one step.
+/// Unlike `pump`, there's no terminal result: frames keep flowing in both
+/// directions until the socket errors out.
+fn channel_pump(chan: &mut ChannelConn, events: EventSet) -> Result<()> {
+    if let ChannelState::Connecting = chan.state {
+        if events.is_writable() || events.is_error() || events.is_hup() {
+            chan.state = ChannelState::Handshaking;
+        } else {
+            return Ok(());
+        }
+    }
+
+    if events.is_writable() {
+        let handshaking = match chan.state {
+            ChannelState::Handshaking => true,
+            _ => false,
+        };
+        if handshaking && chan.handshake_req.remaining() > 0 {
+            chan.handshake_req.write_to(&mut chan.sock)?;
+        }
+        if let ChannelState::Open = chan.state {
+            if chan.out.remaining() > 0 {
+                match chan.tls {
+                    Some(ref mut tls) => {
+                        let data = chan.out.as_bytes().to_vec();
+                        tls.write_plaintext(&data)?;
+                        chan.out.pos = chan.out.len();
+                    }
+                    None => chan.out.write_to(&mut chan.sock)?,
+                }
+                // `out` keeps having more frames appended for the life of
+                // the channel rather than being replaced; drop what's
+                // already gone out instead of growing by every byte ever
+                // sent.
+                chan.out.compact();
+            }
+        }
+        if let Some(ref mut tls) = chan.tls {
+            tls.pump_writable(&mut chan.sock)?;
+        }
+    }
+
+    if events.is_readable() {
+        let mut plain = Vec::new();
+        if chan.tls.is_some() {
+            let tls = chan.tls.as_mut().unwrap();
+            tls.pump_readable(&mut chan.sock, &mut plain)?;
+        } else {
+            let mut buf = [0u8; 4096];
+            match chan.sock.read(&mut buf) {
+                Ok(0) => return Err(box_err!("remote has closed the connection")),
+                Ok(n) => plain.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == WouldBlock => {}
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+
+        if !plain.is_empty() {
+            match chan.state {
+                ChannelState::Handshaking => {
+                    chan.handshake_resp.extend_from_slice(&plain);
+                    if let Some(pos) = find_header_end(&chan.handshake_resp) {
+                        let rest = chan.handshake_resp.split_off(pos);
+                        chan.ws.feed(&rest);
+                        chan.state = ChannelState::Open;
+                    }
+                }
+                ChannelState::Open => chan.ws.feed(&plain),
+                ChannelState::Connecting => {}
+            }
+        }
+    }
+
+    if let ChannelState::Open = chan.state {
+        while let Some((opcode, payload)) = chan.ws.next_frame() {
+            if opcode == http_ws::OPCODE_CLOSE {
+                continue;
+            }
+            let mut msg = Message::new();
+            let result = match msg.merge_from_bytes(&payload) {
+                Ok(()) => Ok(Some(msg)),
+                Err(e) => Err(box_err!("failed to decode channel message: {:?}", e)),
+            };
+            (chan.on_message)(result);
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the wire bytes of a `POST` request for `msg`, applying
+/// `compression` and framing it either with `Content-Length` or
+/// `Transfer-Encoding: chunked`.
+fn encode_request(host: &str, msg: Message, chunked: bool, compression: Compression) -> Result<Body> {
+    let mut body = vec![];
+    msg.write_to_vec(&mut body).map_err(|e| box_err!("failed to encode message: {:?}", e))?;
+    let body = Body::compressed(&body, compression)?;
+
+    let compression_header = if compression != Compression::None {
+        format!("X-Compression: {}\r\n", compression.header_value())
+    } else {
+        String::new()
+    };
+
+    Ok(if chunked {
+        let header = format!("POST {} HTTP/1.1\r\nHost: {}\r\n{}Transfer-Encoding: \
+                               chunked\r\n\r\n",
+                              V1_MSG_PATH,
+                              host,
+                              compression_header);
+        let framed = Body::chunked(body.data);
+        let mut req = Body::default();
+        req.as_mut().extend_from_slice(header.as_bytes());
+        req.as_mut().extend_from_slice(framed.as_bytes());
+        req
+    } else {
+        let header = format!("POST {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\n\r\n",
+                              V1_MSG_PATH,
+                              host,
+                              compression_header,
+                              body.len());
+        let mut req = Body::default();
+        req.as_mut().extend_from_slice(header.as_bytes());
+        req.as_mut().extend_from_slice(body.as_bytes());
+        req
+    })
+}
+
+/// Rewinds a pooled `Conn` back to `WritingRequest` with a freshly encoded
+/// request, reusing its socket (and, for TLS, its already-handshaked
+/// session) instead of dialing again.
+fn reset_for_request(conn: &mut Conn,
+                      url: &Url,
+                      msg: Message,
+                      chunked: bool,
+                      compression: Compression)
+                      -> Result<()> {
+    let host = url.host_str().ok_or_else(|| box_err!("invalid url: {}", url))?;
+    conn.req = encode_request(host, msg, chunked, compression)?;
+    conn.resp_header.clear();
+    conn.resp_body = Body::default();
+    conn.resp_chunked = ChunkedBody::default();
+    conn.resp_compression = Compression::None;
+    conn.idle_since = None;
+    conn.state = ConnState::WritingRequest;
+    Ok(())
+}
+
+impl ClientLoop {
+    fn post(&mut self,
+            event_loop: &mut EventLoop<Self>,
+            url: Url,
+            msg: Message,
+            cb: OnResponse,
+            chunked: bool) {
+        if let Some(key) = host_port_key(&url) {
+            if let Some(token) = self.checkout(&key) {
+                self.reuse(event_loop, token, &url, msg, cb, chunked);
+                return;
+            }
+        }
+
+        let conn = match self.connect(&url, msg, chunked) {
+            Ok(c) => c,
+            Err(e) => return cb.call_box((Err(e),)),
+        };
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(e) = event_loop.register(&conn.sock,
+                                            token,
+                                            EventSet::writable() | EventSet::readable(),
+                                            PollOpt::edge()) {
+            return cb.call_box((Err(box_err!("{:?}", e)),));
+        }
+
+        let mut conn = conn;
+        conn.cb = Some(cb);
+        self.conns.insert(token, conn);
+    }
+
+    /// Pops an idle connection for `key` out of the pool, if one is
+    /// available, leaving it in `conns` for `reuse` to hand a new request.
+    fn checkout(&mut self, key: &str) -> Option<Token> {
+        idle_checkout(&mut self.idle, key)
+    }
+
+    /// Sends a new request down an already-connected, checked-out
+    /// connection instead of dialing a fresh socket.
+    fn reuse(&mut self,
+             event_loop: &mut EventLoop<Self>,
+             token: Token,
+             url: &Url,
+             msg: Message,
+             cb: OnResponse,
+             chunked: bool) {
+        let result = {
+            let compression = self.compression;
+            let conn = self.conns.get_mut(&token).unwrap();
+            reset_for_request(conn, url, msg, chunked, compression)
+        };
+        match result {
+            Ok(()) => {
+                let conn = self.conns.get_mut(&token).unwrap();
+                conn.cb = Some(cb);
+                event_loop.reregister(&conn.sock,
+                                      token,
+                                      EventSet::writable() | EventSet::readable(),
+                                      PollOpt::edge())
+                          .ok();
+            }
+            Err(e) => {
+                if let Some(conn) = self.conns.remove(&token) {
+                    event_loop.deregister(&conn.sock).ok();
+                }
+                cb.call_box((Err(e),));
+            }
+        }
+    }
+
+    /// Moves a connection that just finished a keep-alive response into
+    /// the idle pool, evicting the oldest entry for its host if that
+    /// exceeds `max_idle_per_host`.
+    fn park(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        let key = match self.conns.get_mut(&token) {
+            Some(conn) => {
+                conn.state = ConnState::Idle;
+                conn.idle_since = Some(Instant::now());
+                event_loop.reregister(&conn.sock, token, EventSet::readable(), PollOpt::edge()).ok();
+                conn.key.clone()
+            }
+            None => return,
+        };
+
+        let evict = idle_park(&mut self.idle, key, token, self.pool_config.max_idle_per_host);
+        if let Some(evict) = evict {
+            if let Some(conn) = self.conns.remove(&evict) {
+                event_loop.deregister(&conn.sock).ok();
+            }
+        }
+    }
+
+    fn is_idle(&self, token: Token) -> bool {
+        self.idle.values().any(|tokens| tokens.contains(&token))
+    }
+
+    fn evict_idle(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        idle_evict(&mut self.idle, token);
+        if let Some(conn) = self.conns.remove(&token) {
+            event_loop.deregister(&conn.sock).ok();
+        }
+    }
+
+    /// Closes any idle connection that has outlived `idle_timeout`.
+    fn sweep_idle(&mut self, event_loop: &mut EventLoop<Self>) {
+        let idle_timeout = self.pool_config.idle_timeout;
+        let now = Instant::now();
+        let expired: Vec<Token> = self.conns
+            .iter()
+            .filter(|&(_, conn)| {
+                match conn.idle_since {
+                    Some(since) => now.duration_since(since) >= idle_timeout,
+                    None => false,
+                }
+            })
+            .map(|(&token, _)| token)
+            .collect();
+
+        for token in expired {
+            self.evict_idle(event_loop, token);
+        }
+    }
+
+    fn connect(&self, url: &Url, msg: Message, chunked: bool) -> Result<Conn> {
+        let host = url.host_str().ok_or_else(|| box_err!("invalid url: {}", url))?;
+        let port = url.port().unwrap_or(80);
+        let addr = format!("{}:{}", host, port)
+            .parse()
+            .map_err(|e| box_err!("invalid address: {:?}", e))?;
+        let sock = TcpStream::connect(&addr).map_err(Error::Io)?;
+
+        let tls = self.tls_config.as_ref().map(|cfg| TlsSession::new_client(cfg, host));
+        let key = format!("{}:{}", host, port);
+        let req = encode_request(host, msg, chunked, self.compression)?;
+
+        Ok(Conn {
+            sock: sock,
+            tls: tls,
+            state: ConnState::Connecting,
+            req: req,
+            resp_header: Vec::new(),
+            resp_body: Body::default(),
+            resp_chunked: ChunkedBody::default(),
+            resp_compression: Compression::None,
+            cb: None,
+            key: key,
+            idle_since: None,
+        })
+    }
+
+    fn open_channel(&mut self,
+                     event_loop: &mut EventLoop<Self>,
+                     url: Url,
+                     on_message: OnMessage,
+                     result_tx: mpsc::Sender<Result<Token>>) {
+        let chan = match self.dial_channel(&url, on_message) {
+            Ok(c) => c,
+            Err(e) => {
+                let _ = result_tx.send(Err(e));
+                return;
+            }
+        };
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        if let Err(e) = event_loop.register(&chan.sock,
+                                            token,
+                                            EventSet::writable() | EventSet::readable(),
+                                            PollOpt::edge()) {
+            let _ = result_tx.send(Err(box_err!("{:?}", e)));
+            return;
+        }
+
+        self.channels.insert(token, chan);
+        let _ = result_tx.send(Ok(token));
+    }
+
+    fn dial_channel(&self, url: &Url, on_message: OnMessage) -> Result<ChannelConn> {
+        let host = url.host_str().ok_or_else(|| box_err!("invalid url: {}", url))?;
+        let port = url.port().unwrap_or(80);
+        let addr = format!("{}:{}", host, port)
+            .parse()
+            .map_err(|e| box_err!("invalid address: {:?}", e))?;
+        let sock = TcpStream::connect(&addr).map_err(Error::Io)?;
+
+        let tls = self.tls_config.as_ref().map(|cfg| TlsSession::new_client(cfg, host));
+
+        let header = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: \
+                               Upgrade\r\n\r\n",
+                              url.path(),
+                              host);
+        let mut handshake_req = Body::default();
+        handshake_req.as_mut().extend_from_slice(header.as_bytes());
+
+        Ok(ChannelConn {
+            sock: sock,
+            tls: tls,
+            state: ChannelState::Connecting,
+            handshake_req: handshake_req,
+            handshake_resp: Vec::new(),
+            out: Body::default(),
+            ws: FrameDecoder::default(),
+            on_message: on_message,
+        })
+    }
+
+    fn channel_send(&mut self, event_loop: &mut EventLoop<Self>, token: Token, msg: Message) {
+        let chan = match self.channels.get_mut(&token) {
+            Some(c) => c,
+            None => return,
+        };
+
+        let mut body = vec![];
+        if msg.write_to_vec(&mut body).is_err() {
+            return;
+        }
+        let frame = http_ws::encode_frame(http_ws::OPCODE_BINARY, &body);
+        chan.out.as_mut().extend_from_slice(&frame);
+
+        let _ = event_loop.reregister(&chan.sock,
+                                       token,
+                                       EventSet::writable() | EventSet::readable(),
+                                       PollOpt::edge());
+    }
+}
+
+/// Sends `msgpb::Message`s to peers over HTTP, optionally TLS-encrypted.
+pub struct Client {
+    sender: Sender<ClientMsg>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Client {
+    pub fn new() -> Result<Client> {
+        Client::with_config(None, Compression::None, PoolConfig::default())
+    }
+
+    /// Like `new`, but every outgoing connection is wrapped in a
+    /// `rustls::ClientSession` built from `cfg` before the HTTP request is
+    /// written.
+    pub fn with_tls(cfg: TlsConfig) -> Result<Client> {
+        Client::with_config(Some(cfg), Compression::None, PoolConfig::default())
+    }
+
+    /// Like `new`, but advertises `compression` via `X-Compression` on
+    /// every request and compresses the request body with it; the server
+    /// echoes the header back and compresses its response the same way,
+    /// so responses are decompressed automatically. Peers that don't
+    /// understand the header still get a plain, uncompressed body.
+    pub fn with_compression(compression: Compression) -> Result<Client> {
+        Client::with_config(None, compression, PoolConfig::default())
+    }
+
+    /// Like `new`, but tunes how many idle keep-alive connections per host
+    /// are kept around for reuse, and for how long; see `PoolConfig`.
+    pub fn with_pool_config(cfg: PoolConfig) -> Result<Client> {
+        Client::with_config(None, Compression::None, cfg)
+    }
+
+    fn with_config(cfg: Option<TlsConfig>,
+                   compression: Compression,
+                   pool_config: PoolConfig)
+                   -> Result<Client> {
+        let tls_config = match cfg {
+            Some(ref cfg) => Some(cfg.client_config()?),
+            None => None,
+        };
+
+        let mut event_loop = EventLoop::new().map_err(|e| box_err!("{:?}", e))?;
+        let sender = event_loop.channel();
+        let mut client_loop = ClientLoop {
+            tls_config: tls_config,
+            compression: compression,
+            pool_config: pool_config,
+            conns: HashMap::new(),
+            idle: HashMap::new(),
+            channels: HashMap::new(),
+            next_token: 0,
+        };
+        event_loop.timeout_ms((), POOL_SWEEP_INTERVAL_MS)
+                  .map_err(|e| box_err!("{:?}", e))?;
+
+        let handle = thread::Builder::new()
+            .name("http-client".to_owned())
+            .spawn(move || {
+                let _ = event_loop.run(&mut client_loop);
+            })
+            .map_err(Error::Io)?;
+
+        Ok(Client {
+            sender: sender,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn post_message(&self, url: Url, msg: Message, cb: OnResponse) -> Result<()> {
+        self.sender
+            .send(ClientMsg::Post(url, msg, cb))
+            .map_err(|e| box_err!("{:?}", e))
+    }
+
+    /// Like `post_message`, but frames the request body with
+    /// `Transfer-Encoding: chunked` instead of a `Content-Length`, so a
+    /// large message (e.g. a Raft snapshot) doesn't need its final size
+    /// known up front.
+    pub fn post_message_chunked(&self, url: Url, msg: Message, cb: OnResponse) -> Result<()> {
+        self.sender
+            .send(ClientMsg::PostChunked(url, msg, cb))
+            .map_err(|e| box_err!("{:?}", e))
+    }
+
+    pub fn post_message_timeout(&self,
+                                 url: Url,
+                                 msg: Message,
+                                 timeout: Duration)
+                                 -> Result<OnResponseResult> {
+        let (tx, rx) = mpsc::channel();
+        self.post_message(url,
+                          msg,
+                          box move |res| {
+                              let _ = tx.send(res);
+                          })?;
+        rx.recv_timeout(timeout).map_err(|e| box_err!("{:?}", e))
+    }
+
+    /// Like `post_message`, but returns a `Future` instead of taking a
+    /// callback, so the response can be composed with combinators and
+    /// `and_then` chains. Built on top of `post_message`: the `FnBox`
+    /// callback it's given just completes a oneshot, nothing about the
+    /// request path changes.
+    pub fn post_message_future(&self, url: Url, msg: Message) -> ResponseFuture {
+        self.response_future(url, msg, false)
+    }
+
+    /// Like `post_message_future`, but chunked the same way
+    /// `post_message_chunked` is.
+    pub fn post_message_chunked_future(&self, url: Url, msg: Message) -> ResponseFuture {
+        self.response_future(url, msg, true)
+    }
+
+    fn response_future(&self, url: Url, msg: Message, chunked: bool) -> ResponseFuture {
+        let (tx, rx) = oneshot::channel();
+        let cb = box move |res| {
+            let _ = tx.send(res);
+        };
+        // If the send itself fails, `cb` is dropped along with it without
+        // ever firing; `rx` then resolves with `Canceled` on its own,
+        // which `ResponseFuture::poll` turns into an `Error`.
+        let _ = if chunked {
+            self.post_message_chunked(url, msg, cb)
+        } else {
+            self.post_message(url, msg, cb)
+        };
+        ResponseFuture { rx: rx }
+    }
+
+    /// Opens a persistent, full-duplex `Channel` to `url`: after the HTTP
+    /// `Upgrade` handshake completes, every message received over the
+    /// connection is delivered to `on_message`, rather than just the one
+    /// response a `post_message` callback gets.
+    pub fn open_channel(&self, url: Url, on_message: OnMessage) -> Result<Channel> {
+        let (tx, rx) = mpsc::channel();
+        self.sender
+            .send(ClientMsg::OpenChannel(url, on_message, tx))
+            .map_err(|e| box_err!("{:?}", e))?;
+        let token = rx.recv().map_err(|e| box_err!("{:?}", e))??;
+        Ok(Channel {
+            sender: self.sender.clone(),
+            token: token,
+        })
+    }
+
+    /// Shuts down the event loop thread, which drops every connection it
+    /// was holding - in flight, idle in the pool, or an open `Channel`
+    /// alike.
+    pub fn close(mut self) {
+        let _ = self.sender.send(ClientMsg::Shutdown);
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+/// The `Future` returned by `Client::post_message_future` and
+/// `post_message_chunked_future`. Resolves with the same
+/// `Option<msgpb::Message>` an `OnResponse` callback would have received,
+/// once the oneshot it wraps is completed from that callback.
+pub struct ResponseFuture {
+    rx: oneshot::Receiver<OnResponseResult>,
+}
+
+impl Future for ResponseFuture {
+    type Item = Option<Message>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(box_err!("response sender was dropped before completing")),
+        }
+    }
+}
+
+/// A persistent, full-duplex connection opened by `Client::open_channel`.
+/// Messages enqueued with `send` are framed and written as they're able to
+/// be; replies arrive through the `OnMessage` callback passed to
+/// `open_channel`, not through this handle.
+pub struct Channel {
+    sender: Sender<ClientMsg>,
+    token: Token,
+}
+
+impl Channel {
+    pub fn send(&self, msg: Message) -> Result<()> {
+        self.sender
+            .send(ClientMsg::ChannelSend(self.token, msg))
+            .map_err(|e| box_err!("{:?}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener as StdTcpListener;
+
+    use kvproto::msgpb::MessageType;
+
+    use super::*;
+
+    fn test_conn() -> Conn {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sock = TcpStream::connect(&addr).unwrap();
+        Conn {
+            sock: sock,
+            tls: None,
+            state: ConnState::ReadingHeader,
+            req: Body::default(),
+            resp_header: Vec::new(),
+            resp_body: Body::default(),
+            resp_chunked: ChunkedBody::default(),
+            resp_compression: Compression::None,
+            cb: None,
+            key: "127.0.0.1:1".to_owned(),
+            idle_since: None,
+        }
+    }
+
+    #[test]
+    fn response_completes_when_body_arrives_across_several_reads() {
+        let mut msg = Message::new();
+        msg.set_msg_type(MessageType::Raft);
+        let mut body = vec![];
+        msg.write_to_vec(&mut body).unwrap();
+
+        let mut wire = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len())
+            .into_bytes();
+        wire.extend_from_slice(&body);
+
+        let mut conn = test_conn();
+        let mut result = None;
+        for chunk in wire.chunks(3) {
+            feed_response(&mut conn, chunk);
+            if let Some(r) = response_if_ready(&mut conn) {
+                result = Some(r);
+                break;
+            }
+        }
+
+        let msg1 = result.unwrap().unwrap().unwrap();
+        assert_eq!(msg1.get_msg_type(), MessageType::Raft);
+    }
+
+    #[test]
+    fn idle_checkout_and_park_evict_oldest_over_capacity() {
+        let mut idle = HashMap::new();
+        let key = "127.0.0.1:1".to_owned();
+
+        assert!(idle_checkout(&mut idle, &key).is_none());
+
+        assert!(idle_park(&mut idle, key.clone(), Token(1), 2).is_none());
+        assert!(idle_park(&mut idle, key.clone(), Token(2), 2).is_none());
+        assert_eq!(idle_park(&mut idle, key.clone(), Token(3), 2), Some(Token(1)));
+
+        assert_eq!(idle_checkout(&mut idle, &key), Some(Token(3)));
+        assert_eq!(idle_checkout(&mut idle, &key), Some(Token(2)));
+        assert!(idle_checkout(&mut idle, &key).is_none());
+        assert!(!idle.contains_key(&key));
+    }
+
+    #[test]
+    fn idle_evict_prunes_empty_buckets() {
+        let mut idle = HashMap::new();
+        let key = "127.0.0.1:1".to_owned();
+        idle_park(&mut idle, key.clone(), Token(1), 2);
+
+        idle_evict(&mut idle, Token(1));
+
+        assert!(!idle.contains_key(&key));
+    }
+
+    #[test]
+    fn response_future_resolves_once_callback_completes() {
+        let (tx, rx) = oneshot::channel();
+        let mut fut = ResponseFuture { rx: rx };
+
+        let mut msg = Message::new();
+        msg.set_msg_type(MessageType::Raft);
+        tx.send(Ok(Some(msg))).unwrap();
+
+        match fut.poll() {
+            Ok(Async::Ready(Some(got))) => assert_eq!(got.get_msg_type(), MessageType::Raft),
+            other => panic!("expected the future to resolve with the sent message, got {:?}",
+                             other.is_ok()),
+        }
+    }
+}
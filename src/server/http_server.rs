@@ -0,0 +1,541 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use mio::tcp::{TcpListener, TcpStream};
+use mio::{EventLoop, EventSet, Handler, PollOpt, Sender, Token};
+
+pub use url::Url;
+
+use kvproto::msgpb::Message;
+use protobuf::Message as PbMessage;
+
+use super::{Error, Result};
+use super::http::{Body, ChunkedBody, Compression, OnResponse};
+use super::http_ws::{self, FrameDecoder};
+use super::tls::{TlsConfig, TlsSession};
+
+/// Path the client POSTs a serialized `msgpb::Message` to.
+pub const V1_MSG_PATH: &'static str = "/tikv/raft/v1/message";
+
+const LISTENER: Token = Token(0);
+
+/// Implemented by whoever wants to handle incoming Raft/KV messages.
+pub trait ServerHandler: Send {
+    fn on_request(&mut self, msg: Message, cb: OnResponse);
+}
+
+enum ConnState {
+    ReadingHeader,
+    ReadingBody(usize),
+    ReadingChunked,
+    WritingResponse,
+    /// Upgraded to a persistent, full-duplex `Channel`: every further
+    /// frame read off the socket is dispatched to the handler, and every
+    /// response frame is appended to `resp_body` rather than replacing
+    /// it, so the connection is never torn down between messages.
+    Channel,
+}
+
+struct Conn {
+    sock: TcpStream,
+    tls: Option<TlsSession>,
+    state: ConnState,
+    req_header: Vec<u8>,
+    req_body: Body,
+    req_chunked: ChunkedBody,
+    req_compression: Compression,
+    ws: FrameDecoder,
+    resp_body: Body,
+}
+
+impl Conn {
+    fn new(sock: TcpStream, tls: Option<TlsSession>) -> Conn {
+        Conn {
+            sock: sock,
+            tls: tls,
+            state: ConnState::ReadingHeader,
+            req_header: Vec::new(),
+            req_body: Body::default(),
+            req_chunked: ChunkedBody::default(),
+            req_compression: Compression::None,
+            ws: FrameDecoder::default(),
+            resp_body: Body::default(),
+        }
+    }
+
+    /// Feeds newly arrived plaintext into whichever stage of the request
+    /// is in progress. Returns the decoded messages that became
+    /// available: at most one for a plain request, but possibly several
+    /// at once for a `Channel` that has buffered more than one frame.
+    fn feed(&mut self, data: &[u8]) -> Vec<Message> {
+        if let ConnState::ReadingHeader = self.state {
+            self.req_header.extend_from_slice(data);
+            if let Some(pos) = self.req_header
+                                    .windows(4)
+                                    .position(|w| w == b"\r\n\r\n")
+                                    .map(|p| p + 4) {
+                let body_start = self.req_header.split_off(pos);
+                self.req_compression = compression_header(&self.req_header);
+                if is_upgrade(&self.req_header) {
+                    self.resp_body.reset(0);
+                    self.resp_body.as_mut().extend_from_slice(UPGRADE_RESPONSE.as_bytes());
+                    self.ws.feed(&body_start);
+                    self.state = ConnState::Channel;
+                } else if is_chunked(&self.req_header) {
+                    let _ = self.req_chunked.feed(&body_start);
+                    self.state = ConnState::ReadingChunked;
+                } else {
+                    let len = content_length(&self.req_header).unwrap_or(0);
+                    self.req_body.reset(len);
+                    self.req_body.as_mut().clear();
+                    self.req_body.as_mut().extend_from_slice(&body_start);
+                    self.req_body.pos = body_start.len().min(len);
+                    self.state = ConnState::ReadingBody(len);
+                }
+                return self.feed(&[]);
+            }
+            return vec![];
+        }
+
+        match self.state {
+            ConnState::ReadingBody(len) => {
+                self.req_body.as_mut().extend_from_slice(data);
+                // Completion has to be driven off the accumulated length,
+                // not `pos`: the body can arrive split across any number of
+                // `feed` calls, while `pos` is only ever set once, right
+                // after the header is parsed.
+                if self.req_body.as_bytes().len() >= len {
+                    self.decode(self.req_body.as_bytes().to_vec())
+                        .into_iter()
+                        .collect()
+                } else {
+                    vec![]
+                }
+            }
+            ConnState::ReadingChunked => {
+                let _ = self.req_chunked.feed(data);
+                if self.req_chunked.is_done() {
+                    let body = self.req_chunked.data.clone();
+                    self.decode(body).into_iter().collect()
+                } else {
+                    vec![]
+                }
+            }
+            ConnState::Channel => {
+                self.ws.feed(data);
+                let mut msgs = vec![];
+                while let Some((opcode, payload)) = self.ws.next_frame() {
+                    if opcode == http_ws::OPCODE_CLOSE {
+                        continue;
+                    }
+                    let mut msg = Message::new();
+                    if msg.merge_from_bytes(&payload).is_ok() {
+                        msgs.push(msg);
+                    }
+                }
+                msgs
+            }
+            _ => vec![],
+        }
+    }
+
+    fn decode(&mut self, body: Vec<u8>) -> Option<Message> {
+        let body = match self.req_compression.decompress(&body) {
+            Ok(body) => body,
+            Err(_) => return None,
+        };
+        let mut msg = Message::new();
+        if msg.merge_from_bytes(&body).is_ok() {
+            self.state = ConnState::WritingResponse;
+            Some(msg)
+        } else {
+            None
+        }
+    }
+
+    /// Compresses `resp` with whatever compression the request advertised
+    /// and echoes the same `X-Compression` header back, so `Content-Length`
+    /// reflects the on-wire (compressed) size the client will read. Also
+    /// advertises `Connection: keep-alive`, inviting the client to pool
+    /// this socket instead of closing it after the response.
+    fn set_response(&mut self, resp: Message) {
+        let mut body = vec![];
+        if resp.write_to_vec(&mut body).is_err() {
+            body.clear();
+        }
+        let body = self.req_compression.compress(&body).unwrap_or(body);
+        let compression_header = if self.req_compression != Compression::None {
+            format!("X-Compression: {}\r\n", self.req_compression.header_value())
+        } else {
+            String::new()
+        };
+        let header = format!("HTTP/1.1 200 OK\r\nConnection: keep-alive\r\n{}Content-Length: {}\r\n\r\n",
+                              compression_header,
+                              body.len());
+        self.resp_body.reset(0);
+        self.resp_body.as_mut().extend_from_slice(header.as_bytes());
+        self.resp_body.as_mut().extend_from_slice(&body);
+    }
+
+    /// Rewinds the connection back to `ReadingHeader` once a keep-alive
+    /// response has been fully written, so the next request on the same
+    /// socket is read as if it were a fresh connection.
+    fn reset_for_next_request(&mut self) {
+        self.state = ConnState::ReadingHeader;
+        self.req_header.clear();
+        self.req_body = Body::default();
+        self.req_chunked = ChunkedBody::default();
+        self.req_compression = Compression::None;
+        self.resp_body.reset(0);
+    }
+
+    /// Queues `resp` as one more outgoing frame without disturbing
+    /// whatever is already pending in `resp_body`, so a `Channel` keeps
+    /// flowing both directions instead of being closed after one reply.
+    fn push_frame(&mut self, resp: Message) {
+        let mut body = vec![];
+        if resp.write_to_vec(&mut body).is_err() {
+            body.clear();
+        }
+        let frame = http_ws::encode_frame(http_ws::OPCODE_BINARY, &body);
+        self.resp_body.as_mut().extend_from_slice(&frame);
+    }
+}
+
+const UPGRADE_RESPONSE: &'static str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: \
+                                         websocket\r\nConnection: Upgrade\r\n\r\n";
+
+fn is_upgrade(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header);
+    text.lines().any(|l| {
+        let l = l.to_lowercase();
+        l.starts_with("upgrade:") && l.contains("websocket")
+    })
+}
+
+fn content_length(header: &[u8]) -> Option<usize> {
+    let text = String::from_utf8_lossy(header);
+    text.lines()
+        .find(|l| l.to_lowercase().starts_with("content-length:"))
+        .and_then(|l| l.splitn(2, ':').nth(1))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn is_chunked(header: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(header);
+    text.lines()
+        .any(|l| {
+            l.to_lowercase().starts_with("transfer-encoding:") && l.to_lowercase().contains("chunked")
+        })
+}
+
+fn compression_header(header: &[u8]) -> Compression {
+    let text = String::from_utf8_lossy(header);
+    text.lines()
+        .find(|l| l.to_lowercase().starts_with("x-compression:"))
+        .and_then(|l| l.splitn(2, ':').nth(1))
+        .map(Compression::parse)
+        .unwrap_or(Compression::None)
+}
+
+struct ServerLoop<H: ServerHandler> {
+    listener: TcpListener,
+    handler: H,
+    conns: HashMap<Token, Conn>,
+    tls_config: Option<Arc<::rustls::ServerConfig>>,
+    next_token: usize,
+    sender: Option<Sender<ServerMsg>>,
+}
+
+enum ServerMsg {
+    Respond(Token, Message),
+    RespondChannel(Token, Message),
+    Shutdown,
+}
+
+impl<H: ServerHandler> Handler for ServerLoop<H> {
+    type Timeout = ();
+    type Message = ServerMsg;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
+        if token == LISTENER {
+            while let Ok(Some((sock, _))) = self.listener.accept() {
+                let tls = self.tls_config.as_ref().map(TlsSession::new_server);
+                let token = Token(self.next_token);
+                self.next_token += 1;
+                event_loop.register(&sock, token, EventSet::readable(), PollOpt::edge())
+                          .unwrap();
+                self.conns.insert(token, Conn::new(sock, tls));
+            }
+            return;
+        }
+
+        let (msgs, is_channel) = {
+            let conn = match self.conns.get_mut(&token) {
+                Some(c) => c,
+                None => return,
+            };
+            let msgs = match step(conn, events) {
+                Ok(msgs) => msgs,
+                Err(_) => {
+                    self.conns.remove(&token);
+                    return;
+                }
+            };
+            let is_channel = match conn.state {
+                ConnState::Channel => true,
+                _ => false,
+            };
+            // The upgrade handshake response (or any reply) queued during
+            // `step` needs write interest even though it didn't arrive via
+            // `notify`; a `Channel` additionally always wants to keep
+            // reading for the next frame.
+            let interest = match conn.state {
+                ConnState::Channel => EventSet::readable() | EventSet::writable(),
+                ConnState::WritingResponse => EventSet::writable(),
+                _ => EventSet::readable(),
+            };
+            event_loop.reregister(&conn.sock, token, interest, PollOpt::edge()).ok();
+            (msgs, is_channel)
+        };
+
+        for msg in msgs {
+            let sender = self.sender.clone().unwrap();
+            if is_channel {
+                let cb = box move |res: super::http::OnResponseResult| {
+                    if let Ok(Some(resp)) = res {
+                        let _ = sender.send(ServerMsg::RespondChannel(token, resp));
+                    }
+                };
+                self.handler.on_request(msg, cb);
+            } else {
+                let cb = box move |res: super::http::OnResponseResult| {
+                    if let Ok(Some(resp)) = res {
+                        let _ = sender.send(ServerMsg::Respond(token, resp));
+                    }
+                };
+                self.handler.on_request(msg, cb);
+            }
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: ServerMsg) {
+        match msg {
+            ServerMsg::Respond(token, resp) => {
+                if let Some(conn) = self.conns.get_mut(&token) {
+                    conn.set_response(resp);
+                    event_loop.reregister(&conn.sock,
+                                         token,
+                                         EventSet::writable(),
+                                         PollOpt::edge())
+                              .ok();
+                }
+            }
+            ServerMsg::RespondChannel(token, resp) => {
+                if let Some(conn) = self.conns.get_mut(&token) {
+                    conn.push_frame(resp);
+                    event_loop.reregister(&conn.sock,
+                                         token,
+                                         EventSet::readable() | EventSet::writable(),
+                                         PollOpt::edge())
+                              .ok();
+                }
+            }
+            ServerMsg::Shutdown => event_loop.shutdown(),
+        }
+    }
+}
+
+/// Drives one connection's TLS pump / request read / response write one
+/// step. Returns the decoded requests that became available (usually
+/// zero or one, but a `Channel` may surface several at once).
+fn step(conn: &mut Conn, events: EventSet) -> Result<Vec<Message>> {
+    let mut decoded = vec![];
+
+    if events.is_readable() {
+        if let Some(ref mut tls) = conn.tls {
+            let mut plain = Vec::new();
+            tls.pump_readable(&mut conn.sock, &mut plain)?;
+            if !plain.is_empty() {
+                decoded = conn.feed(&plain);
+            }
+        } else {
+            // Header bytes land straight on the socket; `feed` only hands
+            // them to `Body` once the content length is known, same as
+            // the client side.
+            let mut buf = [0u8; 4096];
+            match conn.sock.read(&mut buf) {
+                Ok(0) => return Err(box_err!("remote has closed the connection")),
+                Ok(n) => decoded = conn.feed(&buf[..n]),
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+    }
+
+    if events.is_writable() {
+        let writing = match conn.state {
+            ConnState::WritingResponse | ConnState::Channel => true,
+            _ => false,
+        };
+        if writing {
+            match conn.tls {
+                Some(ref mut tls) => {
+                    if conn.resp_body.remaining() > 0 {
+                        let data = conn.resp_body.as_bytes().to_vec();
+                        tls.write_plaintext(&data)?;
+                        conn.resp_body.pos = conn.resp_body.len();
+                    }
+                    tls.pump_writable(&mut conn.sock)?;
+                }
+                None => conn.resp_body.write_to(&mut conn.sock)?,
+            }
+        }
+
+        if let ConnState::WritingResponse = conn.state {
+            if conn.resp_body.remaining() == 0 {
+                conn.reset_for_next_request();
+            }
+        }
+
+        if let ConnState::Channel = conn.state {
+            // Unlike `WritingResponse`, a `Channel`'s `resp_body` keeps
+            // having more frames appended for the life of the connection
+            // rather than being replaced; drop what's already gone out
+            // instead of letting it grow by the total bytes ever sent.
+            conn.resp_body.compact();
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// A running `Server`; dropping this does not stop the server, call
+/// `close()` explicitly.
+pub struct Listening {
+    sender: Sender<ServerMsg>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Listening {
+    pub fn close(mut self) {
+        let _ = self.sender.send(ServerMsg::Shutdown);
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+/// Accepts connections and dispatches decoded `msgpb::Message`s to a
+/// `ServerHandler`. Speaks plain HTTP unless built `with_tls`.
+pub struct Server<H: ServerHandler> {
+    handler: H,
+    tls_config: Option<TlsConfig>,
+}
+
+impl<H: ServerHandler + 'static> Server<H> {
+    pub fn new(handler: H) -> Server<H> {
+        Server {
+            handler: handler,
+            tls_config: None,
+        }
+    }
+
+    /// Enables TLS: every accepted socket is wrapped in a
+    /// `rustls::ServerSession` built from `cfg` before any HTTP framing is
+    /// attempted.
+    pub fn with_tls(handler: H, cfg: TlsConfig) -> Server<H> {
+        Server {
+            handler: handler,
+            tls_config: Some(cfg),
+        }
+    }
+
+    pub fn run(self, listener: TcpListener) -> Result<Listening> {
+        let tls_config = match self.tls_config {
+            Some(ref cfg) => Some(cfg.server_config()?),
+            None => None,
+        };
+
+        let mut event_loop = EventLoop::new().map_err(|e| box_err!("{:?}", e))?;
+        event_loop.register(&listener, LISTENER, EventSet::readable(), PollOpt::edge())
+                  .map_err(|e| box_err!("{:?}", e))?;
+        let sender = event_loop.channel();
+
+        let mut server_loop = ServerLoop {
+            listener: listener,
+            handler: self.handler,
+            conns: HashMap::new(),
+            tls_config: tls_config,
+            next_token: 1,
+            sender: Some(sender.clone()),
+        };
+
+        let handle = thread::Builder::new()
+            .name("http-server".to_owned())
+            .spawn(move || {
+                let _ = event_loop.run(&mut server_loop);
+            })
+            .map_err(Error::Io)?;
+
+        Ok(Listening {
+            sender: sender,
+            handle: Some(handle),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener as StdTcpListener;
+
+    use kvproto::msgpb::MessageType;
+
+    use super::*;
+
+    fn test_conn() -> Conn {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sock = TcpStream::connect(&addr).unwrap();
+        Conn::new(sock, None)
+    }
+
+    #[test]
+    fn request_decodes_when_body_arrives_across_several_reads() {
+        let mut msg = Message::new();
+        msg.set_msg_type(MessageType::Raft);
+        let mut body = vec![];
+        msg.write_to_vec(&mut body).unwrap();
+
+        let mut wire = format!("POST {} HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: \
+                                 {}\r\n\r\n",
+                                V1_MSG_PATH,
+                                body.len())
+            .into_bytes();
+        wire.extend_from_slice(&body);
+
+        let mut conn = test_conn();
+        let mut decoded = vec![];
+        for chunk in wire.chunks(3) {
+            decoded.extend(conn.feed(chunk));
+        }
+
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].get_msg_type(), MessageType::Raft);
+    }
+}